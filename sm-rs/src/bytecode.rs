@@ -0,0 +1,304 @@
+//! Compact on-disk encoding for [`Ops`] plus a disassembler.
+//!
+//! Each instruction is a one-byte opcode tag optionally followed by its
+//! operands. `Push`/`Label`/`Jump`/`Reverse` carry a `Word`-sized
+//! little-endian immediate; `Unary`/`Binary` carry a one-byte sub-op tag; the
+//! remaining ops are nullary. This lets a program live in a file and be
+//! inspected independently of the Rust source it was hand-written in.
+#![allow(dead_code)]
+
+use std::fmt;
+
+use crate::{BinaryOp, Ops, UnaryOp, Word};
+
+mod op {
+    pub const NOOP: u8 = 0x00;
+    pub const PUSH: u8 = 0x01;
+    pub const UNARY: u8 = 0x02;
+    pub const BINARY: u8 = 0x03;
+    pub const DUPLICATE: u8 = 0x04;
+    pub const DROP: u8 = 0x05;
+    pub const READ: u8 = 0x06;
+    pub const WRITE: u8 = 0x07;
+    pub const LABEL: u8 = 0x08;
+    pub const JUMP: u8 = 0x09;
+    pub const SKIP_IF_ZERO: u8 = 0x0A;
+    pub const REVERSE: u8 = 0x0B;
+    pub const CALL: u8 = 0x0C;
+    pub const RET: u8 = 0x0D;
+    pub const NATIVE: u8 = 0x0E;
+}
+
+/// Number of bytes an immediate `Word` occupies on disk. Fixed at eight so the
+/// format is stable regardless of the host pointer width.
+const WORD_BYTES: usize = 8;
+
+/// Errors raised while decoding or disassembling a byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisasmError {
+    /// An unknown opcode tag, or an operand that runs past the end of the
+    /// stream.
+    InvalidInstruction(u8),
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisasmError::InvalidInstruction(tag) => {
+                write!(f, "invalid instruction: {:#04x}", tag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DisasmError {}
+
+impl UnaryOp {
+    fn tag(&self) -> u8 {
+        match self {
+            UnaryOp::Add1 => 0,
+            UnaryOp::Sub1 => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(UnaryOp::Add1),
+            1 => Some(UnaryOp::Sub1),
+            _ => None,
+        }
+    }
+}
+
+impl BinaryOp {
+    fn tag(&self) -> u8 {
+        match self {
+            BinaryOp::Add => 0,
+            BinaryOp::Sub => 1,
+            BinaryOp::Mul => 2,
+            BinaryOp::Eq => 3,
+            BinaryOp::Gt => 4,
+            BinaryOp::Geq => 5,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(BinaryOp::Add),
+            1 => Some(BinaryOp::Sub),
+            2 => Some(BinaryOp::Mul),
+            3 => Some(BinaryOp::Eq),
+            4 => Some(BinaryOp::Gt),
+            5 => Some(BinaryOp::Geq),
+            _ => None,
+        }
+    }
+}
+
+fn push_word(out: &mut Vec<u8>, word: Word) {
+    out.extend_from_slice(&(word as u64).to_le_bytes());
+}
+
+/// Serialize a program into the compact byte stream.
+pub fn encode(ops: &[Ops]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for op in ops {
+        match op {
+            Ops::NoOp => out.push(op::NOOP),
+            Ops::Push(w) => {
+                out.push(op::PUSH);
+                push_word(&mut out, *w);
+            }
+            Ops::Unary(u) => {
+                out.push(op::UNARY);
+                out.push(u.tag());
+            }
+            Ops::Binary(b) => {
+                out.push(op::BINARY);
+                out.push(b.tag());
+            }
+            Ops::Duplicate => out.push(op::DUPLICATE),
+            Ops::Drop => out.push(op::DROP),
+            Ops::Read => out.push(op::READ),
+            Ops::Write => out.push(op::WRITE),
+            Ops::Label(w) => {
+                out.push(op::LABEL);
+                push_word(&mut out, *w);
+            }
+            Ops::Jump(w) => {
+                out.push(op::JUMP);
+                push_word(&mut out, *w);
+            }
+            Ops::SkipIfZero => out.push(op::SKIP_IF_ZERO),
+            Ops::Reverse(w) => {
+                out.push(op::REVERSE);
+                push_word(&mut out, *w);
+            }
+            Ops::Call(w) => {
+                out.push(op::CALL);
+                push_word(&mut out, *w);
+            }
+            Ops::Ret => out.push(op::RET),
+            Ops::Native(w) => {
+                out.push(op::NATIVE);
+                push_word(&mut out, *w);
+            }
+        }
+    }
+    out
+}
+
+/// Cursor over a byte stream that pulls out tags, bytes and immediates while
+/// tracking how far it has read.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = self.bytes.get(self.pos).copied()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn word(&mut self, tag: u8) -> Result<Word, DisasmError> {
+        let end = self.pos + WORD_BYTES;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(DisasmError::InvalidInstruction(tag))?;
+        self.pos = end;
+        let mut buf = [0u8; WORD_BYTES];
+        buf.copy_from_slice(slice);
+        Ok(u64::from_le_bytes(buf) as Word)
+    }
+
+    fn sub_op<T>(&mut self, tag: u8, map: fn(u8) -> Option<T>) -> Result<T, DisasmError> {
+        let raw = self.byte().ok_or(DisasmError::InvalidInstruction(tag))?;
+        map(raw).ok_or(DisasmError::InvalidInstruction(tag))
+    }
+
+    fn decode_op(&mut self, tag: u8) -> Result<Ops, DisasmError> {
+        let op = match tag {
+            op::NOOP => Ops::NoOp,
+            op::PUSH => Ops::Push(self.word(tag)?),
+            op::UNARY => Ops::Unary(self.sub_op(tag, UnaryOp::from_tag)?),
+            op::BINARY => Ops::Binary(self.sub_op(tag, BinaryOp::from_tag)?),
+            op::DUPLICATE => Ops::Duplicate,
+            op::DROP => Ops::Drop,
+            op::READ => Ops::Read,
+            op::WRITE => Ops::Write,
+            op::LABEL => Ops::Label(self.word(tag)?),
+            op::JUMP => Ops::Jump(self.word(tag)?),
+            op::SKIP_IF_ZERO => Ops::SkipIfZero,
+            op::REVERSE => Ops::Reverse(self.word(tag)?),
+            op::CALL => Ops::Call(self.word(tag)?),
+            op::RET => Ops::Ret,
+            op::NATIVE => Ops::Native(self.word(tag)?),
+            unknown => return Err(DisasmError::InvalidInstruction(unknown)),
+        };
+        Ok(op)
+    }
+}
+
+/// Reconstruct a program from its byte stream.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Ops>, DisasmError> {
+    let mut reader = Reader::new(bytes);
+    let mut ops = Vec::new();
+    while !reader.done() {
+        let tag = reader.byte().expect("checked by !done()");
+        ops.push(reader.decode_op(tag)?);
+    }
+    Ok(ops)
+}
+
+fn mnemonic(op: &Ops) -> String {
+    match op {
+        Ops::NoOp => "NOOP".to_string(),
+        Ops::Push(w) => format!("PUSH {}", w),
+        Ops::Unary(u) => format!("UNARY {:?}", u),
+        Ops::Binary(b) => format!("BINARY {:?}", b),
+        Ops::Duplicate => "DUP".to_string(),
+        Ops::Drop => "DROP".to_string(),
+        Ops::Read => "READ".to_string(),
+        Ops::Write => "WRITE".to_string(),
+        Ops::Label(w) => format!("LABEL {}", w),
+        Ops::Jump(w) => format!("JUMP {}", w),
+        Ops::SkipIfZero => "SKIPZ".to_string(),
+        Ops::Reverse(w) => format!("REVERSE {}", w),
+        Ops::Call(w) => format!("CALL {}", w),
+        Ops::Ret => "RET".to_string(),
+        Ops::Native(w) => format!("NATIVE {}", w),
+    }
+}
+
+/// Walk the stream and emit a human-readable listing, one instruction per line
+/// prefixed with its byte offset.
+pub fn disasm(bytes: &[u8]) -> Result<String, DisasmError> {
+    let mut reader = Reader::new(bytes);
+    let mut listing = String::new();
+    while !reader.done() {
+        let offset = reader.pos;
+        let tag = reader.byte().expect("checked by !done()");
+        let op = reader.decode_op(tag)?;
+        listing.push_str(&format!("{:04}: {}\n", offset, mnemonic(&op)));
+    }
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<Ops> {
+        vec![
+            Ops::Label(0),
+            Ops::Push(42),
+            Ops::Unary(UnaryOp::Sub1),
+            Ops::Binary(BinaryOp::Gt),
+            Ops::Duplicate,
+            Ops::Reverse(3),
+            Ops::SkipIfZero,
+            Ops::Jump(0),
+            Ops::Write,
+            Ops::NoOp,
+        ]
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let ops = sample();
+        let decoded = decode(&encode(&ops)).unwrap();
+        assert_eq!(ops, decoded);
+    }
+
+    #[test]
+    fn disasm_reports_offsets() {
+        let bytes = encode(&[Ops::Push(7), Ops::Write]);
+        let listing = disasm(&bytes).unwrap();
+        assert_eq!(listing, "0000: PUSH 7\n0009: WRITE\n");
+    }
+
+    #[test]
+    fn unknown_tag_is_invalid() {
+        assert_eq!(decode(&[0xFF]), Err(DisasmError::InvalidInstruction(0xFF)));
+    }
+
+    #[test]
+    fn truncated_operand_is_invalid() {
+        // PUSH tag with only two of the eight immediate bytes present.
+        assert_eq!(
+            decode(&[op::PUSH, 0x01, 0x02]),
+            Err(DisasmError::InvalidInstruction(op::PUSH))
+        );
+    }
+}
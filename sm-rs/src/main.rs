@@ -1,15 +1,53 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+
+mod bytecode;
+mod memory;
+
+use memory::allocation::{Handle, ImmixHeap};
+use memory::stack::{Stack, StackError, DEFAULT_CAPACITY};
 
 type Word = usize;
-type Stack = Vec<Word>;
 type Input = Vec<Word>;
 type Output = Option<Word>;
 
 enum Signals {
     Kill,
     Run(Output),
+    Jumped,
+    Fault(VmError),
+}
+
+/// A runtime fault that halts the VM cleanly with a diagnostic.
+#[derive(Debug)]
+enum VmError {
+    Stack(StackError),
+    UnknownLabel(Word),
+    UnknownNative(Word),
+    HeapAlloc(anyhow::Error),
+}
+
+impl From<StackError> for VmError {
+    fn from(err: StackError) -> Self {
+        VmError::Stack(err)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Stack(err) => write!(f, "{}", err),
+            VmError::UnknownLabel(id) => write!(f, "unknown label {}", id),
+            VmError::UnknownNative(id) => write!(f, "unknown native {}", id),
+            VmError::HeapAlloc(err) => write!(f, "heap allocation failed: {}", err),
+        }
+    }
 }
 
+#[derive(Debug, PartialEq)]
 enum UnaryOp {
     Add1,
     Sub1,
@@ -24,6 +62,7 @@ impl UnaryOp {
     }
 }
 
+#[derive(Debug, PartialEq)]
 enum BinaryOp {
     Add,
     Sub,
@@ -46,6 +85,7 @@ impl BinaryOp {
     }
 }
 
+#[derive(Debug, PartialEq)]
 enum Ops {
     Push(Word),
     Unary(UnaryOp),
@@ -58,53 +98,138 @@ enum Ops {
     Jump(Word),
     SkipIfZero,
     Reverse(Word),
+    Call(Word),
+    Ret,
+    Native(Word),
     NoOp,
 }
 
+/// A native callback the VM can dispatch to. It is handed the operand stack
+/// and reports stack faults back through the usual [`StackError`] channel.
+type NativeFn = Box<dyn Fn(&mut Stack<Word>) -> Result<(), StackError>>;
+
 struct Program {
     ops: Vec<Ops>,
-    stack: RefCell<Stack>,
+    stack: RefCell<Stack<Word>>,
+    calls: RefCell<Stack<Word>>,
+    labels: HashMap<Word, usize>,
+    natives: HashMap<Word, NativeFn>,
     input: RefCell<Input>,
+    /// Backing GC heap every pushed `Word` is allocated against.
+    heap: RefCell<ImmixHeap>,
+    /// Mirrors `stack` one-for-one through `push_stack`/`pop_stack`, giving
+    /// [`ImmixHeap::collect`] a root set. Native callbacks (see [`NativeFn`])
+    /// can pop/push `stack` directly without going through this mirror, so the
+    /// two depths can drift apart: a native that pops more than it pushes
+    /// leaves extra handles here that stay rooted, and one that pushes more
+    /// than it pops lets later `pop_stack` calls drop handles whose values are
+    /// still on `stack`, after which those heap copies can be collected.
+    heap_roots: RefCell<Stack<Handle>>,
     cursor: Word,
     debug:bool,
 }
 
 impl Program {
-    fn get_op(&self) -> Option<&Ops> {
-        self.ops.get(self.cursor)
+    fn new(ops: Vec<Ops>, input: Input, debug: bool) -> Result<Self> {
+        Self::with_stack_capacity(ops, input, debug, DEFAULT_CAPACITY)
     }
 
-    fn reset(&mut self) {
-        self.cursor = 0;
+    fn with_stack_capacity(
+        ops: Vec<Ops>,
+        input: Input,
+        debug: bool,
+        capacity: Word,
+    ) -> Result<Self> {
+        // `jump` used to linearly rescan from 0, so the first occurrence of a
+        // label id always won; keep that semantics here with `or_insert`
+        // rather than letting a later duplicate silently take over.
+        let mut labels = HashMap::new();
+        for (idx, op) in ops.iter().enumerate() {
+            if let Ops::Label(id) = op {
+                labels.entry(*id).or_insert(idx);
+            }
+        }
+
+        Ok(Self {
+            ops,
+            stack: RefCell::new(Stack::with_capacity(capacity)?),
+            calls: RefCell::new(Stack::with_capacity(capacity)?),
+            labels,
+            natives: HashMap::new(),
+            input: RefCell::new(input),
+            heap: RefCell::new(ImmixHeap::new()),
+            heap_roots: RefCell::new(Stack::with_capacity(capacity)?),
+            cursor: 0,
+            debug,
+        })
+    }
+
+    /// Register a host function under `id`, returning the program so
+    /// registrations can be chained onto construction.
+    fn with_native<F>(mut self, id: Word, f: F) -> Self
+    where
+        F: Fn(&mut Stack<Word>) -> Result<(), StackError> + 'static,
+    {
+        self.natives.insert(id, Box::new(f));
+        self
+    }
+
+    fn get_op(&self) -> Option<&Ops> {
+        self.ops.get(self.cursor)
     }
 
     fn right(&mut self) {
         self.cursor += 1;
     }
 
-    fn jump(&mut self, pos: Word) {
-        self.reset();
-        loop {
-            match self.get_op() {
-                None => panic!("JUMP failed: No label found"),
-                Some(op) => match *op {
-                    Ops::Label(o) if o == pos => break,
-                    _ => self.right(),
-                },
+    fn jump(&mut self, pos: Word) -> Result<(), VmError> {
+        match self.labels.get(&pos) {
+            Some(&idx) => {
+                self.cursor = idx;
+                Ok(())
             }
+            None => Err(VmError::UnknownLabel(pos)),
         }
     }
 
-    fn pop_stack(&self) -> Word {
-        self.stack.borrow_mut().pop().unwrap()
+    fn pop_stack(&self) -> Result<Word, VmError> {
+        let value = self.stack.borrow_mut().pop()?;
+        // Best-effort: a native call may have already desynced the depths,
+        // so a missing root here is not itself an error.
+        let _ = self.heap_roots.borrow_mut().pop();
+        Ok(value)
+    }
+
+    fn push_call(&self, addr: Word) -> Result<(), StackError> {
+        self.calls.borrow_mut().push(addr)
+    }
+
+    fn pop_call(&self) -> Result<Word, StackError> {
+        self.calls.borrow_mut().pop()
     }
 
     fn pop_input(&self) -> Option<Word> {
         self.input.borrow_mut().pop()
     }
 
-    fn push_stack(&self, value: Word) {
-        self.stack.borrow_mut().push(value)
+    fn push_stack(&self, value: Word) -> Result<(), VmError> {
+        let handle = self
+            .heap
+            .borrow_mut()
+            .alloc(value)
+            .map_err(VmError::HeapAlloc)?;
+        self.heap_roots.borrow_mut().push(handle)?;
+        self.stack.borrow_mut().push(value)?;
+        Ok(())
+    }
+
+    /// Run a GC cycle over the heap, rooted at the handles in `heap_roots`.
+    /// See [`Program::heap_roots`] for how roots are kept (loosely)
+    /// in sync with the operand stack.
+    fn collect_garbage(&mut self) -> Result<(), anyhow::Error> {
+        let mut roots = self.heap_roots.borrow_mut();
+        let mut heap = self.heap.borrow_mut();
+        heap.collect(roots.as_mut_slice())
     }
 
     fn _no_output() -> Signals {
@@ -112,101 +237,143 @@ impl Program {
     }
 
     fn exec_step(&mut self) -> Signals {
+        match self.try_exec_step() {
+            Ok(sig) => sig,
+            Err(err) => Signals::Fault(err),
+        }
+    }
+
+    fn try_exec_step(&mut self) -> Result<Signals, VmError> {
         use Ops::*;
 
         let cur_op = self.get_op();
 
         match cur_op {
-            None => Signals::Kill,
+            None => Ok(Signals::Kill),
             Some(maybe_op) => match maybe_op {
                 Push(val) => {
-                    self.push_stack(*val);
-                    Self::_no_output()
+                    self.push_stack(*val)?;
+                    Ok(Self::_no_output())
                 }
 
                 Unary(op) => {
-                    let inp = self.pop_stack();
+                    let inp = self.pop_stack()?;
                     let res = op.exec(inp);
-                    self.push_stack(res);
-                    Self::_no_output()
+                    self.push_stack(res)?;
+                    Ok(Self::_no_output())
                 }
 
                 Binary(op) => {
-                    let inp1 = self.pop_stack();
-                    let inp2 = self.pop_stack();
+                    let inp1 = self.pop_stack()?;
+                    let inp2 = self.pop_stack()?;
                     let res = op.exec(inp1, inp2);
-                    self.push_stack(res);
-                    Self::_no_output()
+                    self.push_stack(res)?;
+                    Ok(Self::_no_output())
                 }
                 Read => {
                     match self.pop_input() {
                         Some(res) => {
-                            self.push_stack(res);
-                            Self::_no_output()
+                            self.push_stack(res)?;
+                            Ok(Self::_no_output())
                         }
-                        None => Signals::Kill
+                        None => Ok(Signals::Kill)
                     }
-                    
+
                 }
-                Label(_) => Self::_no_output(),
+                Label(_) => Ok(Self::_no_output()),
 
                 SkipIfZero => {
-                    let res = self.pop_stack();
+                    let res = self.pop_stack()?;
                     if res == 0 {
                         self.right()
                     }
-                    self.push_stack(res);
-                    Self::_no_output()
+                    self.push_stack(res)?;
+                    Ok(Self::_no_output())
                 }
 
                 Duplicate => {
-                    let dup = self.pop_stack();
-                    self.push_stack(dup);
-                    self.push_stack(dup);
-                    Self::_no_output()
+                    let dup = self.pop_stack()?;
+                    self.push_stack(dup)?;
+                    self.push_stack(dup)?;
+                    Ok(Self::_no_output())
                 }
                 Drop => {
-                    self.pop_stack();
-                    Self::_no_output()
+                    self.pop_stack()?;
+                    Ok(Self::_no_output())
                 }
-                Write => Signals::Run(Some(self.pop_stack())),
+                Write => Ok(Signals::Run(Some(self.pop_stack()?))),
 
                 Jump(pos) => {
                     let p = *pos;
-                    self.jump(p);
-                    Self::_no_output()
+                    self.jump(p)?;
+                    Ok(Signals::Jumped)
                 },
+                Call(label) => {
+                    let target = *label;
+                    let ret = self.cursor + 1;
+                    self.push_call(ret)?;
+                    self.jump(target)?;
+                    Ok(Signals::Jumped)
+                }
+                Ret => {
+                    let addr = self.pop_call()?;
+                    self.cursor = addr;
+                    Ok(Signals::Jumped)
+                }
+                Native(id) => {
+                    let id = *id;
+                    match self.natives.get(&id) {
+                        Some(f) => {
+                            f(&mut self.stack.borrow_mut())?;
+                            Ok(Self::_no_output())
+                        }
+                        None => Err(VmError::UnknownNative(id)),
+                    }
+                }
                 Reverse(n) => {
                     let mut tmp: Vec<Word> = Vec::with_capacity(*n);
                     for _ in 0..*n {
-                        match self.stack.borrow_mut().pop() {
-                            None => return Signals::Kill,
-                            Some(v) => tmp.push(v)
-                        }
+                        tmp.push(self.pop_stack()?);
                     }
-                    tmp.iter().for_each(|&v| self.stack.borrow_mut().push(v));
-                    Self::_no_output()
+                    for &v in tmp.iter() {
+                        self.push_stack(v)?;
+                    }
+                    Ok(Self::_no_output())
                 }
 
-                NoOp => Self::_no_output(),
+                NoOp => Ok(Self::_no_output()),
             },
         }
     }
 
     fn run(&mut self) {
+        /// How often (in executed steps) to run a GC cycle over `heap_roots`.
+        const GC_INTERVAL: i64 = 64;
+
         let mut step_count = -1;
         loop {
             step_count += 1;
+            if step_count % GC_INTERVAL == 0 {
+                if let Err(err) = self.collect_garbage() {
+                    println!("#{} Line: {} => GC failed: {}", step_count, self.cursor, err);
+                    break;
+                }
+            }
             if self.debug {
                 println!("#{} Line: {} => Stack: {:?} Input: {:?}", step_count, self.cursor, self.stack.borrow(), self.input.borrow());
             }
-            
+
             match self.exec_step() {
                 Signals::Run(Some(out)) => {
                     println!("Step {} Line {} Output {:?}", step_count, self.cursor, out);
                     self.right()
                 }
                 Signals::Run(None) => self.right(),
+                Signals::Jumped => {}
+                Signals::Fault(err) => {
+                    println!("#{} Line: {} => Fault: {}", step_count, self.cursor, err);
+                    break
+                },
                 Signals::Kill => {
                     if self.debug {
                         println!("#{} Line: {} => Got Kill", step_count, self.cursor);
@@ -218,10 +385,11 @@ impl Program {
     }
 }
 
-fn main() {
+fn main() -> Result<()> {
     use Ops::*;
 
     let code = vec![
+        Native(99), // announce start via the host bridge
         Label(0), // function start
         Read, // Get divisor
         Duplicate, // Dup divisor
@@ -248,15 +416,83 @@ fn main() {
 
     let input: Input = vec![169, 19, 11, 5, 17, 7];
 
-    let stack = Vec::<Word>::with_capacity(10);
-
-    let mut p = Program {
-        ops: code,
-        stack: RefCell::new(stack),
-        cursor: 0,
-        input: RefCell::new(input),
-        debug: false
-    };
+    let mut p = Program::new(code, input, false)?.with_native(99, |_stack| {
+        println!("native: starting remainder calculation");
+        Ok(())
+    });
 
     p.run();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirror `run`'s advance-on-`Run` / stay-put-on-`Jumped` stepping, without
+    /// the GC cadence or debug printing, so tests can assert on `cursor`
+    /// between individual instructions.
+    fn step(p: &mut Program) -> Signals {
+        let sig = p.exec_step();
+        if let Signals::Run(_) = sig {
+            p.right();
+        }
+        sig
+    }
+
+    #[test]
+    fn duplicate_label_resolves_to_first_occurrence() {
+        use Ops::*;
+        let ops = vec![Label(0), Push(1), Label(0), Push(2)];
+        let p = Program::new(ops, vec![], false).unwrap();
+        assert_eq!(p.labels.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn call_ret_transfers_control_and_restores_cursor() {
+        use Ops::*;
+        let ops = vec![
+            Label(0), // 0
+            Call(1),  // 1: call the subroutine at Label(1)
+            Write,    // 2: resumes here after Ret
+            Label(1), // 3: subroutine entry
+            Push(9),  // 4
+            Ret,      // 5
+        ];
+        let mut p = Program::new(ops, vec![], false).unwrap();
+
+        assert!(matches!(step(&mut p), Signals::Run(None))); // Label(0)
+        assert_eq!(p.cursor, 1);
+
+        assert!(matches!(step(&mut p), Signals::Jumped)); // Call(1)
+        assert_eq!(p.cursor, 3);
+
+        assert!(matches!(step(&mut p), Signals::Run(None))); // Label(1)
+        assert_eq!(p.cursor, 4);
+
+        assert!(matches!(step(&mut p), Signals::Run(None))); // Push(9)
+        assert_eq!(p.cursor, 5);
+
+        assert!(matches!(step(&mut p), Signals::Jumped)); // Ret
+        assert_eq!(p.cursor, 2); // back to the instruction right after Call
+
+        assert!(matches!(step(&mut p), Signals::Run(Some(9)))); // Write
+    }
+
+    #[test]
+    fn native_dispatches_registered_host_function() {
+        use Ops::*;
+        let ops = vec![Push(21), Native(0), Write];
+        let mut p = Program::new(ops, vec![], false)
+            .unwrap()
+            .with_native(0, |stack: &mut Stack<Word>| {
+                let v = stack.pop()?;
+                stack.push(v * 2)
+            });
+
+        assert!(matches!(step(&mut p), Signals::Run(None))); // Push(21)
+        assert!(matches!(step(&mut p), Signals::Run(None))); // Native(0)
+        assert!(matches!(step(&mut p), Signals::Run(Some(42)))); // Write
+    }
 }
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
-use std::ptr::write;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::ptr::{copy_nonoverlapping, write, NonNull};
 
 use crate::memory::block::Block;
 
@@ -17,6 +20,45 @@ pub struct BlockMeta {
 }
 
 impl BlockMeta {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            line: [false; LINE_COUNT],
+            black_mark: false,
+        })
+    }
+
+    /// Drop every line and block mark back to the unmarked state. Run at the
+    /// start of a collection before the tracer repopulates the line map.
+    fn clear_marks(&mut self) {
+        self.line = [false; LINE_COUNT];
+        self.black_mark = false;
+    }
+
+    /// Mark the lines spanned by an object of `size` bytes starting at `offset`.
+    /// One extra trailing line is marked conservatively to cover a small object
+    /// that overflowed the end of its starting line.
+    fn mark_lines(&mut self, offset: usize, size: usize) {
+        let first = offset / LINE_SIZE;
+        let last = (offset + size - 1) / LINE_SIZE;
+        for idx in first..=last {
+            if idx < LINE_COUNT {
+                self.line[idx] = true;
+            }
+        }
+        let trailing = last + 1;
+        if trailing < LINE_COUNT {
+            self.line[trailing] = true;
+        }
+        self.black_mark = true;
+    }
+
+    /// Fraction of lines currently marked live, used to pick evacuation
+    /// candidates.
+    fn live_fraction(&self) -> f32 {
+        let live = self.line.iter().filter(|&&l| l).count();
+        live as f32 / LINE_COUNT as f32
+    }
+
     fn find_available_hole(&self, start_at_loc: usize) -> Option<(usize, usize)> {
         let mut free_lines = 0usize;
         let mut start: Option<usize> = None;
@@ -78,6 +120,44 @@ pub struct BumpBlock {
 }
 
 impl BumpBlock {
+    /// Grab a fresh, empty block of [`BLOCK_SIZE`] with an all-clear line map.
+    fn new() -> Result<Self> {
+        Ok(Self {
+            cursor: 0,
+            limit: BLOCK_SIZE,
+            block: Block::new(BLOCK_SIZE)?,
+            meta: BlockMeta::new(),
+        })
+    }
+
+    /// Base address of the backing block, used to map an object pointer back to
+    /// the block that owns it.
+    fn base(&self) -> *const u8 {
+        self.block.as_ptr()
+    }
+
+    /// Does `ptr` fall inside this block?
+    fn owns(&self, ptr: *const u8) -> bool {
+        let base = self.base() as usize;
+        let addr = ptr as usize;
+        addr >= base && addr < base + BLOCK_SIZE
+    }
+
+    /// Re-open this block for bump allocation into the first gap left between
+    /// the lines that survived the last collection.
+    fn recycle(&mut self) {
+        match self.meta.find_available_hole(0) {
+            Some((cursor, limit)) => {
+                self.cursor = cursor;
+                self.limit = limit;
+            }
+            None => {
+                self.cursor = BLOCK_SIZE;
+                self.limit = BLOCK_SIZE;
+            }
+        }
+    }
+
     fn inner_alloc(&mut self, alloc_size: usize) -> Option<*const u8> {
         let next_bump = self.cursor + alloc_size;
 
@@ -99,3 +179,304 @@ impl BumpBlock {
         }
     }
 }
+
+/// Per-object header laid down immediately before each allocated payload. It
+/// carries the epoch the collector last marked it in (see
+/// [`ImmixHeap::epoch`]) and a forwarding word that points at the object's new
+/// home once it has been evacuated.
+struct ObjHeader {
+    mark: usize,
+    size: usize,
+    forward: Option<NonNull<u8>>,
+    edges: EdgeFn,
+}
+
+/// Type-erased edge visitor. Each allocation records the monomorphised
+/// [`Trace::edges`] of its concrete type here so the collector can walk an
+/// object without knowing its Rust type.
+type EdgeFn = fn(*const u8, &mut Vec<*mut Handle>);
+
+const HEADER_SIZE: usize = size_of::<ObjHeader>();
+
+/// Blocks whose live-line fraction drops below this are flagged for evacuation
+/// so their few survivors get compacted into a denser block.
+const EVAC_THRESHOLD: f32 = 0.25;
+
+fn edges_shim<T: Trace>(ptr: *const u8, out: &mut Vec<*mut Handle>) {
+    unsafe { (*(ptr as *const T)).edges(out) }
+}
+
+/// Trait for heap objects the collector can trace. Implementors push a pointer
+/// to each [`Handle`] edge they own; the default is a leaf with no outgoing
+/// references.
+pub trait Trace {
+    fn edges(&self, _out: &mut Vec<*mut Handle>) {}
+}
+
+impl Trace for usize {}
+
+/// A reference to a live heap object. Points at the payload; the [`ObjHeader`]
+/// sits in the [`HEADER_SIZE`] bytes immediately before it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Handle(NonNull<u8>);
+
+impl Handle {
+    fn header(&self) -> *mut ObjHeader {
+        unsafe { self.0.as_ptr().sub(HEADER_SIZE) as *mut ObjHeader }
+    }
+
+    fn payload(&self) -> *const u8 {
+        self.0.as_ptr()
+    }
+
+    /// Reinterpret the payload as a `&T`. The caller is responsible for using
+    /// the same type the object was allocated with.
+    pub unsafe fn as_ref<T>(&self) -> &T {
+        &*(self.0.as_ptr() as *const T)
+    }
+}
+
+/// A large object living outside the block space, on its own dedicated
+/// [`Block`]. Tracked separately because it cannot be bump-allocated.
+struct LargeObject {
+    handle: Handle,
+    _block: Block,
+}
+
+/// Immix-style mark-region heap. Bump-allocates objects into the holes of its
+/// `head` block, retiring full blocks to `rest`, recycling partially filled
+/// ones, and returning empty ones to `free`. Objects larger than a block go to
+/// the `large` list.
+pub struct ImmixHeap {
+    head: Option<BumpBlock>,
+    rest: Vec<BumpBlock>,
+    free: Vec<BumpBlock>,
+    large: Vec<LargeObject>,
+    /// Bumped at the start of every [`collect`](Self::collect). An object's
+    /// header records the epoch it was last marked in, so a survivor from a
+    /// prior cycle reads as unmarked again without the collector having to
+    /// walk every live header up front to reset it.
+    epoch: usize,
+}
+
+impl Default for ImmixHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImmixHeap {
+    pub fn new() -> Self {
+        Self {
+            head: None,
+            rest: Vec::new(),
+            free: Vec::new(),
+            large: Vec::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Allocate `obj` on the heap and return a [`Handle`] to it. Small and
+    /// medium objects bump into a block hole; objects bigger than
+    /// [`BLOCK_SIZE`] are placed on the large-object list.
+    pub fn alloc<T: Trace>(&mut self, obj: T) -> Result<Handle> {
+        let size = HEADER_SIZE + size_of::<T>();
+        if size > BLOCK_SIZE {
+            return self.alloc_large(obj, size);
+        }
+
+        let ptr = self.bump(size)?;
+        let handle = unsafe { Self::emplace(ptr, obj, size) };
+        Ok(handle)
+    }
+
+    /// Write a header and payload at `ptr`, returning a handle to the payload.
+    unsafe fn emplace<T: Trace>(ptr: *const u8, obj: T, size: usize) -> Handle {
+        let header = ObjHeader {
+            mark: 0,
+            size,
+            forward: None,
+            edges: edges_shim::<T>,
+        };
+        write(ptr as *mut ObjHeader, header);
+        let payload = ptr.add(HEADER_SIZE) as *mut u8;
+        write(payload as *mut T, obj);
+        Handle(NonNull::new_unchecked(payload))
+    }
+
+    fn alloc_large<T: Trace>(&mut self, obj: T, size: usize) -> Result<Handle> {
+        let block = Block::new(size.next_power_of_two())?;
+        let handle = unsafe { Self::emplace(block.as_ptr(), obj, size) };
+        self.large.push(LargeObject {
+            handle,
+            _block: block,
+        });
+        Ok(handle)
+    }
+
+    /// Bump `size` bytes out of the head block. When the head has no fitting
+    /// hole it is retired to `rest` and replaced by an empty block from `free`
+    /// or a newly allocated one; blocks in `rest`, recycled ones included, are
+    /// not allocated into again.
+    fn bump(&mut self, size: usize) -> Result<*const u8> {
+        loop {
+            if let Some(head) = self.head.as_mut() {
+                if let Some(ptr) = head.inner_alloc(size) {
+                    return Ok(ptr);
+                }
+            }
+
+            let fresh = match self.free.pop() {
+                Some(block) => block,
+                None => BumpBlock::new()?,
+            };
+            if let Some(old) = self.head.take() {
+                self.rest.push(old);
+            }
+            self.head = Some(fresh);
+        }
+    }
+
+    fn blocks_mut(&mut self) -> impl Iterator<Item = &mut BumpBlock> {
+        self.head.iter_mut().chain(self.rest.iter_mut())
+    }
+
+    /// Collect the heap, reachable from `roots`. Edges are rewritten in place
+    /// as objects are evacuated, so the roots passed in are updated to point at
+    /// the survivors' new locations.
+    pub fn collect(&mut self, roots: &mut [Handle]) -> Result<()> {
+        // Bump the epoch so every header's stale `mark` from a prior cycle
+        // compares unequal, i.e. reads as unmarked, without visiting them.
+        self.epoch = self.epoch.wrapping_add(1);
+        let epoch = self.epoch;
+
+        // Decide which blocks to evacuate from the previous cycle's line map
+        // before we wipe it.
+        let evac: HashSet<usize> = self
+            .blocks_mut()
+            .filter(|b| b.meta.live_fraction() < EVAC_THRESHOLD)
+            .map(|b| b.base() as usize)
+            .collect();
+
+        // Phase 1: clear every block's line array and black mark.
+        for block in self.blocks_mut() {
+            block.meta.clear_marks();
+        }
+
+        // Phase 2: trace from the roots, marking lines and evacuating where
+        // flagged, following and rewriting forwarding pointers as we go.
+        let mut work: Vec<*mut Handle> = roots.iter_mut().map(|h| h as *mut Handle).collect();
+        while let Some(edge) = work.pop() {
+            let handle = unsafe { &mut *edge };
+            let header = unsafe { &mut *handle.header() };
+
+            if let Some(forward) = header.forward {
+                *handle = Handle(forward);
+                continue;
+            }
+            if header.mark == epoch {
+                continue;
+            }
+
+            let live = if self.in_evac(&evac, *handle) {
+                let moved = self.evacuate(*handle, header)?;
+                *handle = moved;
+                moved
+            } else {
+                *handle
+            };
+
+            let header = unsafe { &mut *live.header() };
+            header.mark = epoch;
+            self.mark_lines(live, header.size);
+            (header.edges)(live.payload(), &mut work);
+        }
+
+        // Phase 3: reclaim the block space. Fully unmarked blocks are freed,
+        // partially marked blocks are recycled into their surviving holes.
+        self.sweep();
+        self.large
+            .retain(|obj| unsafe { (*obj.handle.header()).mark } == epoch);
+        Ok(())
+    }
+
+    fn in_evac(&self, evac: &HashSet<usize>, handle: Handle) -> bool {
+        self.head
+            .iter()
+            .chain(self.rest.iter())
+            .find(|b| b.owns(handle.payload()))
+            .map(|b| evac.contains(&(b.base() as usize)))
+            .unwrap_or(false)
+    }
+
+    /// Copy an object into a fresh block and leave a forwarding pointer in the
+    /// old header so later-visited references are redirected.
+    fn evacuate(&mut self, handle: Handle, old_header: &mut ObjHeader) -> Result<Handle> {
+        let size = old_header.size;
+        let dst = self.bump(size)?;
+        unsafe {
+            copy_nonoverlapping(handle.header() as *const u8, dst as *mut u8, size);
+        }
+        let new_payload = unsafe { dst.add(HEADER_SIZE) as *mut u8 };
+        let new_handle = Handle(unsafe { NonNull::new_unchecked(new_payload) });
+        // Clear the stale mark/forward on the copy so it gets traced afresh.
+        unsafe {
+            let moved = &mut *new_handle.header();
+            moved.mark = 0;
+            moved.forward = None;
+        }
+        old_header.forward = Some(unsafe { NonNull::new_unchecked(new_payload) });
+        Ok(new_handle)
+    }
+
+    fn mark_lines(&mut self, handle: Handle, size: usize) {
+        let payload = handle.payload();
+        for block in self.blocks_mut() {
+            if block.owns(payload) {
+                let offset = payload as usize - HEADER_SIZE - block.base() as usize;
+                block.meta.mark_lines(offset, size);
+                return;
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut rest = Vec::new();
+        for mut block in self.rest.drain(..) {
+            if !block.meta.black_mark {
+                block.meta.clear_marks();
+                block.cursor = 0;
+                block.limit = BLOCK_SIZE;
+                self.free.push(block);
+            } else {
+                block.recycle();
+                rest.push(block);
+            }
+        }
+        self.rest = rest;
+
+        if let Some(head) = self.head.as_mut() {
+            head.recycle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_twice_keeps_multi_block_roots_alive() {
+        let mut heap = ImmixHeap::new();
+        // Enough objects to span several BLOCK_SIZE-sized blocks, so the
+        // collector has to walk both fresh and previously-swept blocks.
+        let mut roots: Vec<Handle> = (0..3000usize).map(|i| heap.alloc(i).unwrap()).collect();
+
+        heap.collect(&mut roots).unwrap();
+        heap.collect(&mut roots).unwrap();
+
+        for (i, handle) in roots.iter().enumerate() {
+            assert_eq!(unsafe { *handle.as_ref::<usize>() }, i);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use std::fmt;
+use std::mem::size_of;
+use std::ptr::{read, write};
+
+use crate::memory::block::Block;
+
+/// Default operand-stack depth in words, matching the bounded-stack default
+/// used by the `labast` CLI.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Failure modes of the fixed-capacity stack. These are propagated up to the
+/// VM instead of panicking so a bad program halts cleanly with a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    StackFull,
+    StackEmpty,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackError::StackFull => write!(f, "stack overflow"),
+            StackError::StackEmpty => write!(f, "stack underflow"),
+        }
+    }
+}
+
+/// Fixed-capacity stack backed by a single [`Block`] from the crate allocator.
+///
+/// Three raw pointers carve up the block: `bot` is the lowest writable slot,
+/// `top` is one past the highest, and `cur` is the live top of stack. The
+/// stack grows downward from `top`, so a push decrements `cur` and a pop
+/// increments it. `cur == top` means empty.
+pub struct Stack<T: Copy> {
+    bot: *const T,
+    cur: *mut T,
+    top: *const T,
+    // Keep the backing block alive for as long as the stack points into it.
+    _block: Block,
+}
+
+impl<T: Copy> Stack<T> {
+    /// Allocate a stack holding up to `capacity` values. The backing block is
+    /// rounded up to the next power of two as required by [`Block::new`].
+    pub fn with_capacity(capacity: usize) -> Result<Self> {
+        let bytes = (capacity * size_of::<T>()).next_power_of_two();
+        let block = Block::new(bytes)?;
+
+        let bot = block.as_ptr() as *const T;
+        let top = unsafe { bot.add(capacity) };
+
+        Ok(Self {
+            bot,
+            cur: top as *mut T,
+            top,
+            _block: block,
+        })
+    }
+
+    /// Push a value, returning [`StackError::StackFull`] when the block is
+    /// exhausted.
+    pub fn push(&mut self, value: T) -> Result<(), StackError> {
+        let next = self.cur.wrapping_sub(1);
+        if (next as *const T) < self.bot {
+            return Err(StackError::StackFull);
+        }
+        unsafe { write(next, value) };
+        self.cur = next;
+        Ok(())
+    }
+
+    /// Pop the top value, returning [`StackError::StackEmpty`] on underflow.
+    pub fn pop(&mut self) -> Result<T, StackError> {
+        if std::ptr::eq(self.cur, self.top) {
+            return Err(StackError::StackEmpty);
+        }
+        let value = unsafe { read(self.cur) };
+        self.cur = unsafe { self.cur.add(1) };
+        Ok(value)
+    }
+
+    /// Expose the live elements as a mutable slice, so a GC can use them as
+    /// roots without copying the stack.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        let len = (self.top as usize - self.cur as usize) / size_of::<T>();
+        unsafe { std::slice::from_raw_parts_mut(self.cur, len) }
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Stack<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        let mut p = self.top;
+        while p > (self.cur as *const T) {
+            p = unsafe { p.sub(1) };
+            list.entry(unsafe { &*p });
+        }
+        list.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_past_capacity_is_stack_full() {
+        let mut s: Stack<usize> = Stack::with_capacity(4).unwrap();
+        for i in 0..4 {
+            s.push(i).unwrap();
+        }
+        assert_eq!(s.push(4), Err(StackError::StackFull));
+    }
+
+    #[test]
+    fn pop_below_empty_is_stack_empty() {
+        let mut s: Stack<usize> = Stack::with_capacity(4).unwrap();
+        s.push(1).unwrap();
+        assert_eq!(s.pop(), Ok(1));
+        assert_eq!(s.pop(), Err(StackError::StackEmpty));
+    }
+}